@@ -1,14 +1,17 @@
 use std::convert::TryFrom;
 use std::io;
 use std::path::PathBuf;
-use std::process::{self, Command, Stdio};
 
+use chrono::{DateTime, Duration, Utc};
 use dirs::home_dir;
 use ini::Ini;
 use lazy_static::lazy_static;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use structopt::StructOpt;
 
+#[cfg(feature = "cli")]
+use std::process::{Command, Stdio};
+
 lazy_static! {
     static ref AWS_CONFIG_PATH: PathBuf = {
         let mut config_path = home_dir().expect("Unable to determine home directory");
@@ -16,9 +19,15 @@ lazy_static! {
         config_path.push("config");
         config_path
     };
+    static ref AWS_CREDENTIALS_PATH: PathBuf = {
+        let mut credentials_path = home_dir().expect("Unable to determine home directory");
+        credentials_path.push(".aws");
+        credentials_path.push("credentials");
+        credentials_path
+    };
 }
 
-#[derive(Debug, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 struct App {
     /// The profile to assume.
     #[structopt(required_unless = "role-arn")]
@@ -35,67 +44,444 @@ struct App {
     /// An external ID to use when assuming a specific ARN.
     #[structopt(long, conflicts_with = "profile", requires = "role-arn")]
     external_id: Option<String>,
+
+    /// The role session name to record with the assumed session.
+    #[structopt(long)]
+    role_session_name: Option<String>,
+
+    /// The lifetime of the assumed session, in seconds (900–43200).
+    #[structopt(long)]
+    duration_seconds: Option<i64>,
+
+    /// Assume the role via web identity, reading the OIDC token from this file.
+    #[structopt(long)]
+    web_identity_token_file: Option<String>,
+
+    /// The output format for the resolved credentials.
+    #[structopt(
+        long,
+        default_value = "shell",
+        possible_values = &["shell", "json", "powershell", "fish", "env-file"]
+    )]
+    format: Format,
+
+    /// Force a new session, ignoring any cached credentials.
+    #[structopt(long)]
+    refresh: bool,
+
+    /// Bypass the credential cache entirely, neither reading nor writing it.
+    #[structopt(long)]
+    no_cache: bool,
+
+    /// Spawn a subshell (or the command given after `--`) with the assumed
+    /// credentials injected into its environment, instead of printing them.
+    #[structopt(long)]
+    exec: bool,
+
+    /// Serve the assumed session from a loopback credential endpoint (the ECS
+    /// container-credentials protocol) for the spawned subshell and its children.
+    #[structopt(long, conflicts_with = "exec")]
+    agent: bool,
+
+    /// The command to run under `--exec`; defaults to the user's `$SHELL`.
+    #[structopt(last = true)]
+    command: Vec<String>,
 }
 
-fn main() {
-    if let Err(error) = _main() {
+#[tokio::main]
+async fn main() {
+    if let Err(error) = _main().await {
         eprintln!("Error: {}", error);
-        process::exit(1);
+        std::process::exit(1);
     }
 }
 
-fn _main() -> Result<(), AppError> {
+async fn _main() -> Result<(), AppError> {
     let app = App::from_args();
 
-    let args = AwsArgs::try_from(app)?;
-    let mut cmd = Command::new("aws");
-    cmd.args(args).stdout(Stdio::piped());
+    if app.agent {
+        return agent::run(app).await;
+    }
+
+    let format = app.format;
+    let refresh = app.refresh;
+    let no_cache = app.no_cache;
+    let exec = app.exec;
+    let command = app.command.clone();
+    let cache_key = cache::key(&app);
 
-    let child = cmd.spawn()?;
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        eprintln!();
-        return Err(AppError::CmdError(cmd));
+    let cached = if !no_cache && !refresh {
+        cache::load(&cache_key)?.filter(|c| !c.is_expired())
+    } else {
+        None
+    };
+
+    let credentials = match cached {
+        Some(credentials) => credentials,
+        None => {
+            let args = AwsArgs::try_from(app)?;
+            let credentials = args.assume().await?;
+            if !no_cache {
+                cache::store(&cache_key, &credentials)?;
+            }
+            credentials
+        }
+    };
+
+    if exec {
+        exec_subshell(&credentials, &command)
+    } else {
+        println!("{}", format.render(&credentials));
+        Ok(())
     }
-    let response = serde_json::from_slice::<CredentialsResponse>(&output.stdout)?;
-    println!("{}", response.credentials);
+}
+
+/// Spawn `command` (or the user's shell) with the assumed credentials set in
+/// its environment, wait for it, and exit with its status code.
+fn exec_subshell(credentials: &SessionCredentials, command: &[String]) -> Result<(), AppError> {
+    let mut cmd = match command.split_first() {
+        Some((program, args)) => {
+            let mut cmd = std::process::Command::new(program);
+            cmd.args(args);
+            cmd
+        }
+        None => std::process::Command::new(default_shell()),
+    };
+    cmd.env("AWS_ACCESS_KEY_ID", &credentials.access_key_id)
+        .env("AWS_SECRET_ACCESS_KEY", &credentials.secret_access_key)
+        .env("AWS_SESSION_TOKEN", &credentials.session_token);
 
-    Ok(())
+    let status = cmd.spawn()?.wait()?;
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn default_shell() -> String {
+    if let Some(shell) = std::env::var_os("SHELL") {
+        return shell.to_string_lossy().into_owned();
+    }
+    if cfg!(windows) {
+        "cmd.exe".to_string()
+    } else {
+        "/bin/sh".to_string()
+    }
+}
+
+/// Resolve and assume a fresh session from the given invocation, re-running the
+/// full profile resolution (and re-prompting for MFA only if the profile needs it).
+async fn resolve_and_assume(app: &App) -> Result<SessionCredentials, AppError> {
+    let args = AwsArgs::try_from(app.clone())?;
+    args.assume().await
+}
+
+mod agent {
+    //! Loopback credential-serving agent.
+    //!
+    //! Holds the assumed session in memory and serves it over a loopback HTTP
+    //! endpoint using the ECS container-credentials protocol, exporting
+    //! `AWS_CONTAINER_CREDENTIALS_FULL_URI` and `AWS_CONTAINER_AUTHORIZATION_TOKEN`
+    //! into a spawned subshell so any number of child SDK clients share one
+    //! transparently-refreshing session without credentials touching disk.
+
+    use std::convert::Infallible;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use hyper::header::AUTHORIZATION;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use serde_derive::Serialize;
+    use tokio::sync::oneshot;
+
+    use super::{default_shell, resolve_and_assume, App, AppError, SessionCredentials};
+
+    /// The credential document an ECS-protocol client expects, with the
+    /// `Token` field name the SDKs look for (rather than `SessionToken`).
+    #[derive(Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct EcsCredentials {
+        access_key_id: String,
+        secret_access_key: String,
+        token: String,
+        expiration: String,
+    }
+
+    impl From<&SessionCredentials> for EcsCredentials {
+        fn from(credentials: &SessionCredentials) -> Self {
+            EcsCredentials {
+                access_key_id: credentials.access_key_id.clone(),
+                secret_access_key: credentials.secret_access_key.clone(),
+                token: credentials.session_token.clone(),
+                expiration: credentials.expiration.clone(),
+            }
+        }
+    }
+
+    pub(super) async fn run(app: App) -> Result<(), AppError> {
+        let command = app.command.clone();
+        let session = Arc::new(Mutex::new(resolve_and_assume(&app).await?));
+        let auth_token = auth_token()?;
+
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let uri = format!("http://{}/", listener.local_addr()?);
+
+        // Serve credentials to any child presenting the authorization token.
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server_session = Arc::clone(&session);
+        let server_token = auth_token.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let session = Arc::clone(&server_session);
+            let token = server_token.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    serve(req, Arc::clone(&session), token.clone())
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener)
+            .map_err(|error| error.to_string())?
+            .serve(make_service)
+            .with_graceful_shutdown(async {
+                let _ = shutdown_rx.await;
+            });
+        let server = tokio::spawn(server);
+
+        // Re-assume in the background as the session nears expiry.
+        let refresh_app = app.clone();
+        let refresh_session = Arc::clone(&session);
+        let refresher = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                let expired = refresh_session
+                    .lock()
+                    .map(|session| session.is_expired())
+                    .unwrap_or(true);
+                if expired {
+                    match resolve_and_assume(&refresh_app).await {
+                        Ok(fresh) => {
+                            if let Ok(mut session) = refresh_session.lock() {
+                                *session = fresh;
+                            }
+                        }
+                        Err(error) => eprintln!("Error refreshing session: {}", error),
+                    }
+                }
+            }
+        });
+
+        // Run the subshell (or requested command) against the live endpoint.
+        let status = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            let mut cmd = match command.split_first() {
+                Some((program, args)) => {
+                    let mut cmd = std::process::Command::new(program);
+                    cmd.args(args);
+                    cmd
+                }
+                None => std::process::Command::new(default_shell()),
+            };
+            cmd.env("AWS_CONTAINER_CREDENTIALS_FULL_URI", &uri)
+                .env("AWS_CONTAINER_AUTHORIZATION_TOKEN", &auth_token);
+            cmd.spawn()?.wait()
+        })
+        .await
+        .map_err(|error| error.to_string())??;
+
+        let _ = shutdown_tx.send(());
+        refresher.abort();
+        let _ = server.await;
+
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    async fn serve(
+        req: Request<Body>,
+        session: Arc<Mutex<SessionCredentials>>,
+        auth_token: String,
+    ) -> Result<Response<Body>, Infallible> {
+        let authorized = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value == auth_token)
+            .unwrap_or(false);
+        if !authorized {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        let body = {
+            let session = session.lock().expect("session mutex poisoned");
+            serde_json::to_string(&EcsCredentials::from(&*session))
+                .expect("credentials are always serializable")
+        };
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    /// A cryptographically random per-run authorization token, so only our
+    /// spawned children can read the credential endpoint.
+    fn auth_token() -> Result<String, AppError> {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes).map_err(|error| error.to_string())?;
+        Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
 }
 
 struct AwsArgs {
+    /// The static profile whose credentials seed the chain, if any.
     source_profile: Option<String>,
+    /// Intermediate roles to assume before the target, innermost (closest to
+    /// the static source) first. Empty unless the source profile is itself a role.
+    chain: Vec<AssumeRoleArgs>,
     subcommand: AwsSubcommand,
 }
 
+impl AwsArgs {
+    /// Resolve the requested session credentials, driving either the native STS
+    /// client or — with the `cli` feature — the `aws` command-line tool.
+    async fn assume(self) -> Result<SessionCredentials, AppError> {
+        #[cfg(not(feature = "cli"))]
+        {
+            self.assume_native().await
+        }
+        #[cfg(feature = "cli")]
+        {
+            self.assume_cli()
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    fn assume_cli(self) -> Result<SessionCredentials, AppError> {
+        let mut cmd = Command::new("aws");
+        cmd.args(self).stdout(Stdio::piped());
+
+        let child = cmd.spawn()?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            eprintln!();
+            return Err(AppError::CmdError(cmd));
+        }
+        let response = serde_json::from_slice::<CredentialsResponse>(&output.stdout)?;
+        Ok(response.credentials)
+    }
+}
+
 enum AwsSubcommand {
     AssumeRole(AssumeRoleArgs),
+    AssumeRoleWithWebIdentity(WebIdentityArgs),
     GetSessionToken(GetSessionTokenArgs),
 }
 
 struct AssumeRoleArgs {
     role_arn: String,
+    role_session_name: String,
+    duration_seconds: Option<i64>,
     external_id: Option<String>,
     mfa: Option<(String, String)>,
 }
 
 impl AssumeRoleArgs {
-    fn new(role_arn: String, external_id: Option<String>, mfa: Option<(String, String)>) -> Self {
+    fn new(
+        role_arn: String,
+        role_session_name: String,
+        duration_seconds: Option<i64>,
+        external_id: Option<String>,
+        mfa: Option<(String, String)>,
+    ) -> Self {
         AssumeRoleArgs {
             role_arn,
+            role_session_name,
+            duration_seconds,
             external_id,
             mfa,
         }
     }
 }
 
+struct WebIdentityArgs {
+    role_arn: String,
+    role_session_name: String,
+    duration_seconds: Option<i64>,
+    /// The OIDC token, read from the configured `web_identity_token_file`.
+    web_identity_token: String,
+}
+
+impl WebIdentityArgs {
+    fn new(
+        role_arn: String,
+        role_session_name: String,
+        duration_seconds: Option<i64>,
+        token_file: &str,
+    ) -> Result<Self, AppError> {
+        let web_identity_token = std::fs::read_to_string(token_file)?.trim().to_string();
+        Ok(WebIdentityArgs {
+            role_arn,
+            role_session_name,
+            duration_seconds,
+            web_identity_token,
+        })
+    }
+}
+
 struct GetSessionTokenArgs {
+    duration_seconds: Option<i64>,
     mfa: Option<(String, String)>,
 }
 
 impl GetSessionTokenArgs {
-    fn new(mfa: Option<(String, String)>) -> Self {
-        GetSessionTokenArgs { mfa }
+    fn new(duration_seconds: Option<i64>, mfa: Option<(String, String)>) -> Self {
+        GetSessionTokenArgs {
+            duration_seconds,
+            mfa,
+        }
+    }
+}
+
+/// A meaningful default role session name: `<os-user>-<unix-timestamp>`.
+fn default_session_name() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "assume-role".to_string());
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    format!("{}-{}", user, timestamp)
+}
+
+/// Validate a session duration against STS's accepted 900–43200s range.
+fn validate_duration(duration_seconds: Option<i64>) -> Result<Option<i64>, AppError> {
+    if let Some(duration) = duration_seconds {
+        if !(900..=43200).contains(&duration) {
+            return Err(format!(
+                "duration_seconds must be between 900 and 43200, got {}",
+                duration
+            )
+            .into());
+        }
+    }
+    Ok(duration_seconds)
+}
+
+/// Session settings supplied on the command line, overriding any matching
+/// profile keys on the *target* profile.
+struct Overrides {
+    role_session_name: Option<String>,
+    duration_seconds: Option<i64>,
+    web_identity_token_file: Option<String>,
+}
+
+impl From<&App> for Overrides {
+    fn from(app: &App) -> Self {
+        Overrides {
+            role_session_name: app.role_session_name.clone(),
+            duration_seconds: app.duration_seconds,
+            web_identity_token_file: app.web_identity_token_file.clone(),
+        }
     }
 }
 
@@ -103,58 +489,379 @@ impl TryFrom<App> for AwsArgs {
     type Error = AppError;
 
     fn try_from(app: App) -> Result<Self, Self::Error> {
+        let overrides = Overrides::from(&app);
         if let Some(name) = app.profile {
-            let profile = Ini::load_from_file(&(*AWS_CONFIG_PATH))?
-                .delete(Some(format!("profile {}", name)))
-                .ok_or_else(|| {
-                    format!("profile \"{}\" not found in {:?}", name, *AWS_CONFIG_PATH)
-                })?;
-            AwsArgs::try_from(profile)
+            let config = Ini::load_from_file(&(*AWS_CONFIG_PATH))?;
+            resolve_profile(&config, &name, &overrides)
         } else {
-            Ok(AwsArgs {
-                source_profile: app.source_profile,
-                subcommand: AwsSubcommand::AssumeRole(AssumeRoleArgs::new(
-                    app.role_arn.unwrap(),
+            let role_arn = app.role_arn.unwrap();
+            let role_session_name = overrides
+                .role_session_name
+                .unwrap_or_else(default_session_name);
+            let duration_seconds = validate_duration(overrides.duration_seconds)?;
+            let subcommand = match overrides.web_identity_token_file {
+                Some(token_file) => AwsSubcommand::AssumeRoleWithWebIdentity(WebIdentityArgs::new(
+                    role_arn,
+                    role_session_name,
+                    duration_seconds,
+                    &token_file,
+                )?),
+                None => AwsSubcommand::AssumeRole(AssumeRoleArgs::new(
+                    role_arn,
+                    role_session_name,
+                    duration_seconds,
                     app.external_id,
                     None,
                 )),
+            };
+            Ok(AwsArgs {
+                source_profile: app.source_profile,
+                chain: Vec::new(),
+                subcommand,
             })
         }
     }
 }
 
-impl TryFrom<ini::ini::Properties> for AwsArgs {
-    type Error = AppError;
+/// Resolve a named profile into the session to obtain, following
+/// `source_profile` references recursively: when a source profile is itself a
+/// role, it becomes an intermediate `assume-role` in the chain, and resolution
+/// continues until a profile with static credentials (or an MFA-only session)
+/// is reached.
+fn resolve_profile(config: &Ini, target: &str, overrides: &Overrides) -> Result<AwsArgs, AppError> {
+    // A static source profile's credentials live in `~/.aws/credentials`, so we
+    // consult it as well as the config to tell a legitimate static source apart
+    // from a typo'd, nonexistent one.
+    let credentials = Ini::load_from_file(&(*AWS_CREDENTIALS_PATH)).ok();
+    // Roles collected target-first; reversed into `chain` at the end.
+    let mut roles = Vec::new();
+    let mut visited = Vec::new();
+    let mut current = target.to_string();
 
-    fn try_from(mut properties: ini::ini::Properties) -> Result<Self, Self::Error> {
-        let source_profile = properties.remove("source_profile");
-        let role_arn = properties.remove("role_arn");
-        let mfa = properties
-            .remove("mfa_serial")
-            .map(|mfa_serial| -> Result<_, io::Error> {
-                eprint!("MFA token: ");
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                Ok((mfa_serial, input.trim().to_string()))
-            })
-            .transpose()?;
+    loop {
+        let is_target = current == target;
+        if visited.iter().any(|name| name == &current) {
+            visited.push(current);
+            return Err(format!("source_profile cycle detected: {}", chain_path(&visited)).into());
+        }
+        visited.push(current.clone());
 
-        Ok(match role_arn {
-            Some(role_arn) => AwsArgs {
-                source_profile,
-                subcommand: AwsSubcommand::AssumeRole(AssumeRoleArgs::new(role_arn, None, mfa)),
-            },
-            None => AwsArgs {
+        let properties = config
+            .section(Some(format!("profile {}", current)))
+            .ok_or_else(|| {
+                format!(
+                    "profile \"{}\" not found in {:?} (chain: {})",
+                    current,
+                    *AWS_CONFIG_PATH,
+                    chain_path(&visited)
+                )
+            })?;
+        let source_profile = properties.get("source_profile").map(str::to_string);
+        let role_arn = properties.get("role_arn").map(str::to_string);
+        let external_id = properties.get("external_id").map(str::to_string);
+        let mfa = read_mfa(properties.get("mfa_serial").map(str::to_string))?;
+
+        // Command-line overrides only apply to the target profile.
+        let role_session_name = is_target
+            .then(|| overrides.role_session_name.clone())
+            .flatten()
+            .or_else(|| properties.get("role_session_name").map(str::to_string))
+            .unwrap_or_else(default_session_name);
+        let duration_seconds = validate_duration(
+            is_target
+                .then_some(overrides.duration_seconds)
+                .flatten()
+                .or(parse_duration(properties.get("duration_seconds"))?),
+        )?;
+        let web_identity_token_file = is_target
+            .then(|| overrides.web_identity_token_file.clone())
+            .flatten()
+            .or_else(|| properties.get("web_identity_token_file").map(str::to_string));
+
+        match role_arn {
+            Some(role_arn) => {
+                // A web-identity profile produces credentials without a source,
+                // so it cannot act as an intermediate link in a source chain.
+                if web_identity_token_file.is_some() && !is_target {
+                    return Err(format!(
+                        "web-identity profile \"{}\" cannot be used as a source_profile (chain: {})",
+                        current,
+                        chain_path(&visited)
+                    )
+                    .into());
+                }
+                // A web-identity role needs no source credentials, so it stands
+                // alone rather than extending the source-profile chain.
+                if let (true, Some(token_file)) = (is_target, web_identity_token_file) {
+                    return Ok(AwsArgs {
+                        source_profile: None,
+                        chain: Vec::new(),
+                        subcommand: AwsSubcommand::AssumeRoleWithWebIdentity(WebIdentityArgs::new(
+                            role_arn,
+                            role_session_name,
+                            duration_seconds,
+                            &token_file,
+                        )?),
+                    });
+                }
+                roles.push(AssumeRoleArgs::new(
+                    role_arn,
+                    role_session_name,
+                    duration_seconds,
+                    external_id,
+                    mfa,
+                ));
+                // If the source profile is itself a role, keep walking the chain.
+                match source_profile {
+                    Some(source)
+                        if config
+                            .section(Some(format!("profile {}", source)))
+                            .and_then(|p| p.get("role_arn"))
+                            .is_some() =>
+                    {
+                        current = source;
+                    }
+                    Some(source) if !profile_exists(config, credentials.as_ref(), &source) => {
+                        visited.push(source);
+                        return Err(format!(
+                            "source_profile references a profile that does not exist (chain: {})",
+                            chain_path(&visited)
+                        )
+                        .into());
+                    }
+                    source => {
+                        // `source` holds static credentials (or nothing).
+                        let subcommand = AwsSubcommand::AssumeRole(roles.remove(0));
+                        roles.reverse();
+                        return Ok(AwsArgs {
+                            source_profile: source,
+                            chain: roles,
+                            subcommand,
+                        });
+                    }
+                }
+            }
+            None => {
+                // No role to assume: fall back to a session token for this profile.
+                return Ok(AwsArgs {
+                    source_profile,
+                    chain: Vec::new(),
+                    subcommand: AwsSubcommand::GetSessionToken(GetSessionTokenArgs::new(
+                        duration_seconds,
+                        mfa,
+                    )),
+                });
+            }
+        }
+    }
+}
+
+/// Parse a `duration_seconds` profile value into an integer.
+fn parse_duration(value: Option<&str>) -> Result<Option<i64>, AppError> {
+    value
+        .map(|value| {
+            value
+                .parse::<i64>()
+                .map_err(|_| format!("invalid duration_seconds \"{}\"", value).into())
+        })
+        .transpose()
+}
+
+/// Whether `name` is defined as a profile, either as a `profile <name>` section
+/// in the config or as a `[<name>]` section in the credentials file.
+fn profile_exists(config: &Ini, credentials: Option<&Ini>, name: &str) -> bool {
+    config.section(Some(format!("profile {}", name))).is_some()
+        || credentials
+            .and_then(|credentials| credentials.section(Some(name.to_string())))
+            .is_some()
+}
+
+/// Render a resolution path (e.g. `a -> b -> c`) for inclusion in errors.
+fn chain_path(names: &[String]) -> String {
+    names.join(" -> ")
+}
+
+/// Prompt for an MFA token code when a serial number is configured.
+fn read_mfa(mfa_serial: Option<String>) -> Result<Option<(String, String)>, io::Error> {
+    mfa_serial
+        .map(|mfa_serial| {
+            eprint!("MFA token: ");
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            Ok((mfa_serial, input.trim().to_string()))
+        })
+        .transpose()
+}
+
+#[cfg(not(feature = "cli"))]
+mod native {
+    //! Native STS backend.
+    //!
+    //! Resolves the source profile's static credentials from `~/.aws/credentials`
+    //! and `~/.aws/config`, then SigV4-signs calls to `sts:AssumeRole` /
+    //! `sts:GetSessionToken` over HTTPS instead of shelling out to the `aws` CLI.
+
+    use rusoto_core::credential::{ProfileProvider, StaticProvider};
+    use rusoto_core::{HttpClient, Region};
+    use rusoto_sts::{
+        AssumeRoleRequest, AssumeRoleWithWebIdentityRequest, Credentials, GetSessionTokenRequest,
+        Sts, StsClient,
+    };
+
+    use super::{
+        AppError, AssumeRoleArgs, AwsArgs, AwsSubcommand, GetSessionTokenArgs, SessionCredentials,
+        WebIdentityArgs, AWS_CREDENTIALS_PATH,
+    };
+
+    impl AwsArgs {
+        pub(super) async fn assume_native(self) -> Result<SessionCredentials, AppError> {
+            let AwsArgs {
                 source_profile,
-                subcommand: AwsSubcommand::GetSessionToken(GetSessionTokenArgs::new(mfa)),
-            },
+                chain,
+                subcommand,
+            } = self;
+
+            // Web identity assumption is unauthenticated and has no source
+            // profile chain, so it stands apart from the static-credential path.
+            if let AwsSubcommand::AssumeRoleWithWebIdentity(args) = subcommand {
+                let client = anonymous_client()?;
+                return Ok(args.assume(&client).await?.into());
+            }
+
+            // Seed the chain with the static source profile, then assume each
+            // intermediate role in turn, feeding its output into the next client.
+            let mut session: Option<Credentials> = None;
+            for role in chain {
+                let client = client_for(session.as_ref(), source_profile.as_deref())?;
+                session = Some(role.assume(&client).await?);
+            }
+
+            let client = client_for(session.as_ref(), source_profile.as_deref())?;
+            let credentials = match subcommand {
+                AwsSubcommand::AssumeRole(args) => args.assume(&client).await?,
+                AwsSubcommand::GetSessionToken(args) => args.assume(&client).await?,
+                AwsSubcommand::AssumeRoleWithWebIdentity(_) => unreachable!(),
+            };
+            Ok(credentials.into())
+        }
+    }
+
+    /// Build an STS client with empty credentials for the unauthenticated
+    /// `AssumeRoleWithWebIdentity` call, which requires no source credentials.
+    fn anonymous_client() -> Result<StsClient, AppError> {
+        let dispatcher = HttpClient::new().map_err(|error| error.to_string())?;
+        let provider = StaticProvider::new_minimal(String::new(), String::new());
+        Ok(StsClient::new_with(dispatcher, provider, Region::default()))
+    }
+
+    /// Build an STS client from the previous step's temporary credentials, or —
+    /// at the head of the chain — from the static source profile.
+    fn client_for(
+        session: Option<&Credentials>,
+        source_profile: Option<&str>,
+    ) -> Result<StsClient, AppError> {
+        let dispatcher = HttpClient::new().map_err(|error| error.to_string())?;
+        Ok(match session {
+            Some(session) => {
+                let provider = StaticProvider::new(
+                    session.access_key_id.clone(),
+                    session.secret_access_key.clone(),
+                    Some(session.session_token.clone()),
+                    None,
+                );
+                StsClient::new_with(dispatcher, provider, Region::default())
+            }
+            None => {
+                let mut provider =
+                    ProfileProvider::with_default_configuration(&*AWS_CREDENTIALS_PATH);
+                if let Some(profile) = source_profile {
+                    provider.set_profile(profile);
+                }
+                StsClient::new_with(dispatcher, provider, Region::default())
+            }
         })
     }
+
+    impl AssumeRoleArgs {
+        async fn assume(self, client: &StsClient) -> Result<rusoto_sts::Credentials, AppError> {
+            let (serial_number, token_code) = match self.mfa {
+                Some((serial, token)) => (Some(serial), Some(token)),
+                None => (None, None),
+            };
+            let response = client
+                .assume_role(AssumeRoleRequest {
+                    role_arn: self.role_arn,
+                    role_session_name: self.role_session_name,
+                    duration_seconds: self.duration_seconds,
+                    external_id: self.external_id,
+                    serial_number,
+                    token_code,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| error.to_string())?;
+            response
+                .credentials
+                .ok_or_else(|| "STS returned no credentials".to_string().into())
+        }
+    }
+
+    impl WebIdentityArgs {
+        async fn assume(self, client: &StsClient) -> Result<rusoto_sts::Credentials, AppError> {
+            let response = client
+                .assume_role_with_web_identity(AssumeRoleWithWebIdentityRequest {
+                    role_arn: self.role_arn,
+                    role_session_name: self.role_session_name,
+                    duration_seconds: self.duration_seconds,
+                    web_identity_token: self.web_identity_token,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| error.to_string())?;
+            response
+                .credentials
+                .ok_or_else(|| "STS returned no credentials".to_string().into())
+        }
+    }
+
+    impl GetSessionTokenArgs {
+        async fn assume(self, client: &StsClient) -> Result<rusoto_sts::Credentials, AppError> {
+            let (serial_number, token_code) = match self.mfa {
+                Some((serial, token)) => (Some(serial), Some(token)),
+                None => (None, None),
+            };
+            let response = client
+                .get_session_token(GetSessionTokenRequest {
+                    duration_seconds: self.duration_seconds,
+                    serial_number,
+                    token_code,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| error.to_string())?;
+            response
+                .credentials
+                .ok_or_else(|| "STS returned no credentials".to_string().into())
+        }
+    }
+
+    impl From<rusoto_sts::Credentials> for SessionCredentials {
+        fn from(credentials: rusoto_sts::Credentials) -> Self {
+            SessionCredentials {
+                access_key_id: credentials.access_key_id,
+                secret_access_key: credentials.secret_access_key,
+                session_token: credentials.session_token,
+                expiration: credentials.expiration,
+            }
+        }
+    }
 }
 
+#[cfg(feature = "cli")]
 #[derive(Default)]
 struct ArgsBuilder(Vec<String>);
 
+#[cfg(feature = "cli")]
 impl ArgsBuilder {
     fn push(&mut self, arg: &str) {
         self.0.push(arg.to_string());
@@ -172,10 +879,12 @@ impl ArgsBuilder {
     }
 }
 
+#[cfg(feature = "cli")]
 trait ExtendArgs {
     fn extend(self, args: &mut ArgsBuilder);
 }
 
+#[cfg(feature = "cli")]
 impl IntoIterator for AwsArgs {
     type Item = String;
     type IntoIter = std::vec::IntoIter<Self::Item>;
@@ -187,6 +896,7 @@ impl IntoIterator for AwsArgs {
     }
 }
 
+#[cfg(feature = "cli")]
 impl ExtendArgs for AwsArgs {
     fn extend(self, args: &mut ArgsBuilder) {
         args.push_flag_opt("--profile", self.source_profile);
@@ -195,21 +905,28 @@ impl ExtendArgs for AwsArgs {
     }
 }
 
+#[cfg(feature = "cli")]
 impl ExtendArgs for AwsSubcommand {
     fn extend(self, args: &mut ArgsBuilder) {
         match self {
             AwsSubcommand::AssumeRole(a) => a.extend(args),
+            AwsSubcommand::AssumeRoleWithWebIdentity(a) => a.extend(args),
             AwsSubcommand::GetSessionToken(a) => a.extend(args),
         }
     }
 }
 
+#[cfg(feature = "cli")]
 impl ExtendArgs for AssumeRoleArgs {
     fn extend(self, args: &mut ArgsBuilder) {
         args.push("assume-role");
         args.push_flag("--role-arn", self.role_arn);
-        args.push_flag("--role-session-name", "blah".to_string());
-        args.push_flag_opt("--external_id", self.external_id);
+        args.push_flag("--role-session-name", self.role_session_name);
+        args.push_flag_opt(
+            "--duration-seconds",
+            self.duration_seconds.map(|d| d.to_string()),
+        );
+        args.push_flag_opt("--external-id", self.external_id);
         if let Some((mfa_serial, mfa_token)) = self.mfa {
             args.push_flag("--serial-number", mfa_serial);
             args.push_flag("--token-code", mfa_token);
@@ -217,9 +934,28 @@ impl ExtendArgs for AssumeRoleArgs {
     }
 }
 
+#[cfg(feature = "cli")]
+impl ExtendArgs for WebIdentityArgs {
+    fn extend(self, args: &mut ArgsBuilder) {
+        args.push("assume-role-with-web-identity");
+        args.push_flag("--role-arn", self.role_arn);
+        args.push_flag("--role-session-name", self.role_session_name);
+        args.push_flag_opt(
+            "--duration-seconds",
+            self.duration_seconds.map(|d| d.to_string()),
+        );
+        args.push_flag("--web-identity-token", self.web_identity_token);
+    }
+}
+
+#[cfg(feature = "cli")]
 impl ExtendArgs for GetSessionTokenArgs {
     fn extend(self, args: &mut ArgsBuilder) {
         args.push("get-session-token");
+        args.push_flag_opt(
+            "--duration-seconds",
+            self.duration_seconds.map(|d| d.to_string()),
+        );
         if let Some((mfa_serial, mfa_token)) = self.mfa {
             args.push_flag("--serial-number", mfa_serial);
             args.push_flag("--token-code", mfa_token);
@@ -227,38 +963,233 @@ impl ExtendArgs for GetSessionTokenArgs {
     }
 }
 
+#[cfg(feature = "cli")]
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct CredentialsResponse {
     credentials: SessionCredentials,
 }
 
-#[derive(Deserialize)]
+mod cache {
+    //! On-disk credential cache.
+    //!
+    //! Cached sessions live under `~/.cache/assume-role`, one JSON file per
+    //! cache key, holding the session's `AccessKeyId`, `SecretAccessKey`,
+    //! `SessionToken` and `Expiration`; the rest of the STS response (such as
+    //! the assumed-role user) is not stored. A cached entry is reused until it
+    //! expires (see [`SessionCredentials::is_expired`]).
+
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use dirs::cache_dir;
+
+    use super::{App, AppError, SessionCredentials};
+
+    /// Derive a cache key from the requested identity, so distinct
+    /// (profile / role_arn / source_profile / mfa_serial) combinations never
+    /// collide. A named profile carries its own `source_profile` and
+    /// `mfa_serial` via the config, so the profile name alone disambiguates it;
+    /// for a direct `--role-arn` invocation the source profile is folded in so
+    /// the same role assumed from different sources does not share a cache entry.
+    pub(super) fn key(app: &App) -> String {
+        let identity = match &app.profile {
+            Some(profile) => profile.clone(),
+            None => [
+                app.role_arn.as_deref().unwrap_or("default"),
+                app.source_profile.as_deref().unwrap_or(""),
+            ]
+            .join(":"),
+        };
+        // Hex-encode the identity so that distinct spellings (e.g. `prod-a` and
+        // `prod.a`) never collapse onto the same file and serve each other's
+        // credentials.
+        identity.bytes().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn path(key: &str) -> Option<PathBuf> {
+        let mut path = cache_dir()?;
+        path.push("assume-role");
+        path.push(format!("{}.json", key));
+        Some(path)
+    }
+
+    /// Load a cached session, if one has been stored for `key`.
+    pub(super) fn load(key: &str) -> Result<Option<SessionCredentials>, AppError> {
+        let path = match path(key) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let contents = fs::read(path)?;
+        Ok(serde_json::from_slice(&contents).ok())
+    }
+
+    /// Write `credentials` to the cache under `key`, creating the cache
+    /// directory if necessary. The directory is restricted to `0700` and the
+    /// file created `0600`, since it holds live STS secrets.
+    pub(super) fn store(key: &str, credentials: &SessionCredentials) -> Result<(), AppError> {
+        let path = path(key).ok_or_else(|| "unable to determine cache directory".to_string())?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+            restrict_dir(parent)?;
+        }
+        let contents = serde_json::to_vec_pretty(credentials).map_err(|error| error.to_string())?;
+        write_private(&path, &contents)?;
+        Ok(())
+    }
+
+    /// Create (or truncate) `path` with owner-only `0600` permissions and write
+    /// `contents` to it.
+    #[cfg(unix)]
+    fn write_private(path: &Path, contents: &[u8]) -> Result<(), AppError> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(contents)?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn write_private(path: &Path, contents: &[u8]) -> Result<(), AppError> {
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Restrict the cache directory to owner-only `0700`.
+    #[cfg(unix)]
+    fn restrict_dir(path: &Path) -> Result<(), AppError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn restrict_dir(_path: &Path) -> Result<(), AppError> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 struct SessionCredentials {
     access_key_id: String,
     secret_access_key: String,
     session_token: String,
+    expiration: String,
 }
 
-impl std::fmt::Display for SessionCredentials {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "export AWS_ACCESS_KEY_ID='{}'
+impl SessionCredentials {
+    /// Whether the session has expired, or will within the refresh skew.
+    fn is_expired(&self) -> bool {
+        match DateTime::parse_from_rfc3339(&self.expiration) {
+            Ok(expiration) => expiration <= Utc::now() + Duration::minutes(5),
+            // Treat an unparseable expiry as already expired so we refresh.
+            Err(_) => true,
+        }
+    }
+}
+
+/// The ways a resolved session can be rendered to stdout.
+#[derive(Clone, Copy, Debug)]
+enum Format {
+    /// POSIX `export VAR=...` statements (the default).
+    Shell,
+    /// The `credential_process` JSON schema understood by the AWS SDKs.
+    Json,
+    /// PowerShell `$Env:VAR = ...` statements.
+    Powershell,
+    /// fish `set -gx VAR ...` statements.
+    Fish,
+    /// A `KEY=value` env file, one variable per line.
+    EnvFile,
+}
+
+impl Format {
+    fn render(self, credentials: &SessionCredentials) -> String {
+        let SessionCredentials {
+            access_key_id,
+            secret_access_key,
+            session_token,
+            expiration,
+        } = credentials;
+        match self {
+            Format::Shell => format!(
+                "export AWS_ACCESS_KEY_ID='{}'
 export AWS_SECRET_ACCESS_KEY='{}'
 export AWS_SESSION_TOKEN='{}'",
-            self.access_key_id, self.secret_access_key, self.session_token
-        )
+                access_key_id, secret_access_key, session_token
+            ),
+            Format::Json => serde_json::to_string(&CredentialProcessOutput {
+                version: 1,
+                access_key_id,
+                secret_access_key,
+                session_token,
+                expiration,
+            })
+            .expect("credential process output is always serializable"),
+            Format::Powershell => format!(
+                "$Env:AWS_ACCESS_KEY_ID = '{}'
+$Env:AWS_SECRET_ACCESS_KEY = '{}'
+$Env:AWS_SESSION_TOKEN = '{}'",
+                access_key_id, secret_access_key, session_token
+            ),
+            Format::Fish => format!(
+                "set -gx AWS_ACCESS_KEY_ID '{}'
+set -gx AWS_SECRET_ACCESS_KEY '{}'
+set -gx AWS_SESSION_TOKEN '{}'",
+                access_key_id, secret_access_key, session_token
+            ),
+            Format::EnvFile => format!(
+                "AWS_ACCESS_KEY_ID={}
+AWS_SECRET_ACCESS_KEY={}
+AWS_SESSION_TOKEN={}",
+                access_key_id, secret_access_key, session_token
+            ),
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shell" => Ok(Format::Shell),
+            "json" => Ok(Format::Json),
+            "powershell" => Ok(Format::Powershell),
+            "fish" => Ok(Format::Fish),
+            "env-file" => Ok(Format::EnvFile),
+            _ => Err(format!("unknown format \"{}\"", s)),
+        }
     }
 }
 
+/// The exact object an external `credential_process` is expected to emit.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CredentialProcessOutput<'a> {
+    version: u8,
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    session_token: &'a str,
+    expiration: &'a str,
+}
+
 #[derive(Debug)]
 enum AppError {
+    #[cfg(feature = "cli")]
     CmdError(Command),
     Generic(String),
     Io(io::Error),
     ProfileError(ini::ini::Error),
+    #[cfg(feature = "cli")]
     UnexpectedOutput(serde_json::Error),
 }
 
@@ -280,6 +1211,7 @@ impl From<ini::ini::Error> for AppError {
     }
 }
 
+#[cfg(feature = "cli")]
 impl From<serde_json::Error> for AppError {
     fn from(error: serde_json::Error) -> Self {
         AppError::UnexpectedOutput(error)
@@ -289,12 +1221,14 @@ impl From<serde_json::Error> for AppError {
 impl std::fmt::Display for AppError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            #[cfg(feature = "cli")]
             AppError::CmdError(cmd) => write!(f, "AWS CLI call failed: {:?}", cmd),
             AppError::Generic(message) => write!(f, "{}", message),
             AppError::Io(error) => write!(f, "{}", error),
             AppError::ProfileError(error) => {
                 write!(f, "unable to read {:?}: {}", *AWS_CONFIG_PATH, error)
             }
+            #[cfg(feature = "cli")]
             AppError::UnexpectedOutput(error) => {
                 write!(f, "unexpected output from AWS CLI call: {}", error)
             }